@@ -1,6 +1,9 @@
 use {
   std::cmp,
-  std::sync::{Arc, RwLock},
+  std::collections::{BTreeMap, HashMap, HashSet},
+  std::str::FromStr,
+  std::sync::{Arc, Mutex, MutexGuard, RwLock},
+  arc_swap::ArcSwap,
   crypto::{
     digest::Digest,
     sha3::Sha3,
@@ -13,31 +16,553 @@ use {
 },
 };
 
+// Serializes a key or value into the bytes that get folded into a node's
+// hash. Kept as a local trait (rather than requiring `AsRef<[u8]>`) so it
+// can be implemented for foreign types like `Pubkey` and `AccountSharedData`.
+pub trait Encodable {
+  fn encode(&self) -> Vec<u8>;
+}
+
+macro_rules! impl_encodable_for_int {
+  ($($t:ty),*) => {
+    $(
+      impl Encodable for $t {
+        fn encode(&self) -> Vec<u8> {
+          self.to_be_bytes().to_vec()
+        }
+      }
+    )*
+  };
+}
+
+impl_encodable_for_int!(u8, u32, u64, i32, i64);
+
+impl Encodable for Pubkey {
+  fn encode(&self) -> Vec<u8> {
+    self.to_bytes().to_vec()
+  }
+}
+
+impl Encodable for AccountSharedData {
+  fn encode(&self) -> Vec<u8> {
+    let data = self.data();
+    let mut bytes = Vec::with_capacity(8 + 32 + 1 + 8 + data.len());
+    bytes.extend_from_slice(&self.lamports().to_be_bytes());
+    bytes.extend_from_slice(self.owner().as_ref());
+    bytes.push(self.executable() as u8);
+    bytes.extend_from_slice(&self.rent_epoch().to_be_bytes());
+    bytes.extend_from_slice(data);
+    bytes
+  }
+}
+
+// Parameterizes `Node`/`IAVL` over the hash function used for leaf and
+// inner node hashes, so the committed root and any Merkle proofs stay
+// consistent with whatever commitment scheme a downstream verifier
+// expects without forking this module. `K`/`V` are bounded per call
+// (mirroring `Encodable`), not on the trait itself, since a hasher has no
+// data of its own to constrain.
+pub trait TreeHasher: Clone + Default {
+  fn hash_leaf<K: Encodable, V: Encodable>(key: &K, value: &V, version: u32) -> [u8; 32];
+  fn hash_inner(height: u8, version: u32, left_hash: &[u8; 32], right_hash: &[u8; 32]) -> [u8; 32];
+}
+
+// The original hashing backend: SHA3-256 throughout. The default `H` for
+// every `Node`/`IAVL` so existing call sites that don't name a hasher keep
+// compiling unchanged.
+#[derive(Clone, Copy, Default)]
+pub struct Sha3Hasher;
+
+impl TreeHasher for Sha3Hasher {
+  fn hash_leaf<K: Encodable, V: Encodable>(key: &K, value: &V, version: u32) -> [u8; 32] {
+    leaf_hash(key, value, version)
+  }
+
+  fn hash_inner(height: u8, version: u32, left_hash: &[u8; 32], right_hash: &[u8; 32]) -> [u8; 32] {
+    inner_hash(height, version, left_hash, right_hash)
+  }
+}
+
+// leaf_hash = SHA3_256(0x00 || encode(version) || encode(key) || SHA3_256(value_bytes))
+fn leaf_hash<K: Encodable, V: Encodable>(key: &K, value: &V, version: u32) -> [u8; 32] {
+  let mut value_hasher = Sha3::sha3_256();
+  value_hasher.input(&value.encode());
+  let mut value_hash = [0u8; 32];
+  value_hasher.result(&mut value_hash);
+
+  let mut hasher = Sha3::sha3_256();
+  hasher.input(&[0x00]);
+  hasher.input(&version.encode());
+  hasher.input(&key.encode());
+  hasher.input(&value_hash);
+  let mut h = [0u8; 32];
+  hasher.result(&mut h);
+  h
+}
+
+// inner_hash = SHA3_256(0x01 || height || encode(version) || left_hash || right_hash)
+// Notably this does not depend on the node's key: an inner node's hash
+// commits to its subtrees' contents, not to the split point between them.
+fn inner_hash(height: u8, version: u32, left_hash: &[u8; 32], right_hash: &[u8; 32]) -> [u8; 32] {
+  let mut hasher = Sha3::sha3_256();
+  hasher.input(&[0x01]);
+  hasher.input(&[height]);
+  hasher.input(&version.encode());
+  hasher.input(left_hash);
+  hasher.input(right_hash);
+  let mut h = [0u8; 32];
+  hasher.result(&mut h);
+  h
+}
+
+// A BLAKE3-backed alternative to `Sha3Hasher`. BLAKE3 is substantially
+// faster for the path rehash every `insert`/`remove` performs,
+// and its own internal tree structure fits internal-node hashing well.
+// Uses the same `0x00`/`0x01` domain separation and field layout as
+// `Sha3Hasher`, so the two backends differ only in the underlying
+// primitive, not the commitment scheme.
+#[derive(Clone, Copy, Default)]
+pub struct Blake3Hasher;
+
+impl TreeHasher for Blake3Hasher {
+  fn hash_leaf<K: Encodable, V: Encodable>(key: &K, value: &V, version: u32) -> [u8; 32] {
+    let value_hash = blake3::hash(&value.encode());
+
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&[0x00]);
+    hasher.update(&version.encode());
+    hasher.update(&key.encode());
+    hasher.update(value_hash.as_bytes());
+    *hasher.finalize().as_bytes()
+  }
+
+  fn hash_inner(height: u8, version: u32, left_hash: &[u8; 32], right_hash: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&[0x01]);
+    hasher.update(&[height]);
+    hasher.update(&version.encode());
+    hasher.update(left_hash);
+    hasher.update(right_hash);
+    *hasher.finalize().as_bytes()
+  }
+}
+
+// Either a real, fully materialized subtree (`Restore`'s own accumulator),
+// an opaque subtree attested by a `RangeProof`'s frontier, or the result of
+// `join`ing across the two. `join` only ever needs a side's height, hash,
+// and the ability to split it into children, so it combines real and
+// attested subtrees uniformly without the frontier side ever needing real
+// key/value data.
+enum Side<K, V, H> {
+  Real(Arc<Node<K, V, H>>),
+  Frontier(FrontierNode),
+  Joined { height: u8, hash: [u8; 32], left: Box<Side<K, V, H>>, right: Box<Side<K, V, H>> },
+}
+
+impl<K, V, H> Clone for Side<K, V, H> {
+  fn clone(&self) -> Self {
+    match self {
+      Side::Real(node) => Side::Real(node.clone()),
+      Side::Frontier(node) => Side::Frontier(node.clone()),
+      Side::Joined { height, hash, left, right } => {
+        Side::Joined { height: *height, hash: *hash, left: left.clone(), right: right.clone() }
+      }
+    }
+  }
+}
+
+impl<K, V, H> Side<K, V, H> {
+  fn height(&self) -> u8 {
+    match self {
+      Side::Real(node) => Node::height(node),
+      Side::Frontier(node) => node.height(),
+      Side::Joined { height, .. } => *height,
+    }
+  }
+
+  fn hash(&self) -> [u8; 32] {
+    match self {
+      Side::Real(node) => Node::node_hash(node),
+      Side::Frontier(node) => node.hash(),
+      Side::Joined { hash, .. } => *hash,
+    }
+  }
+
+  // Splits into (left, right), or `None` if this side is a leaf.
+  fn decompose(&self) -> Option<(Side<K, V, H>, Side<K, V, H>)>
+  where
+    K: Copy,
+  {
+    match self {
+      Side::Real(node) => match node.as_ref() {
+        Node::Leaf { .. } => None,
+        Node::Inner { left, right, .. } => Some((Side::Real(left.clone()), Side::Real(right.clone()))),
+      },
+      Side::Frontier(FrontierNode::Leaf { .. }) => None,
+      Side::Frontier(FrontierNode::Inner { left, right, .. }) => {
+        Some((Side::Frontier((**left).clone()), Side::Frontier((**right).clone())))
+      }
+      Side::Joined { left, right, .. } => Some(((**left).clone(), (**right).clone())),
+    }
+  }
+}
+
+// Combines two already-finalized sides into their parent, the same way
+// `Node::inner` does for a real insert: if both sides are real, the result
+// is a genuine `Arc<Node>` (so `Restore::finish` ends up with a working
+// tree); otherwise the result only carries the height/hash bookkeeping a
+// `RangeProof`'s attested remainder can support.
+fn side_inner<K, V, H>(left: Side<K, V, H>, right: Side<K, V, H>, version: u32) -> Side<K, V, H>
+where
+  K: Copy,
+  H: TreeHasher,
+{
+  if let (Side::Real(l), Side::Real(r)) = (&left, &right) {
+    let split = Node::min_key(r);
+    return Side::Real(Node::inner(split, l.clone(), r.clone(), version));
+  }
+  let height = cmp::max(left.height(), right.height()) + 1;
+  let hash = H::hash_inner(height, version, &left.hash(), &right.hash());
+  Side::Joined { height, hash, left: Box::new(left), right: Box::new(right) }
+}
+
+// The four generic counterparts of `Node::rotate_right`/`rotate_left`/
+// `rotate_right_left`/`rotate_left_right`, operating on `Side` instead of
+// `Arc<Node>` so `join` can rebalance even when one side is an attested
+// (not materialized) subtree.
+fn side_rotate_right<K, V, H>(root: Side<K, V, H>, version: u32) -> Side<K, V, H>
+where
+  K: Copy,
+  H: TreeHasher,
+{
+  let (r, root_right) = root.decompose().expect("rotate_right always sees an inner node");
+  let (r_left, r_right) = r.decompose().expect("rotate_right's pivot is always an inner node");
+  let new_root = side_inner(r_right, root_right, version);
+  side_inner(r_left, new_root, version)
+}
+
+fn side_rotate_left<K, V, H>(root: Side<K, V, H>, version: u32) -> Side<K, V, H>
+where
+  K: Copy,
+  H: TreeHasher,
+{
+  let (root_left, r) = root.decompose().expect("rotate_left always sees an inner node");
+  let (r_left, r_right) = r.decompose().expect("rotate_left's pivot is always an inner node");
+  let new_root = side_inner(root_left, r_left, version);
+  side_inner(new_root, r_right, version)
+}
+
+fn side_rotate_right_left<K, V, H>(root: Side<K, V, H>, version: u32) -> Side<K, V, H>
+where
+  K: Copy,
+  H: TreeHasher,
+{
+  let (left, right) = root.decompose().expect("rotate_right_left always sees an inner node");
+  let (r_left, r_right) = right.decompose().expect("rotate_right_left's right side is always an inner node");
+  let new_right = if r_left.height() > r_right.height() { side_rotate_right(right, version) } else { right };
+  side_rotate_left(side_inner(left, new_right, version), version)
+}
+
+fn side_rotate_left_right<K, V, H>(root: Side<K, V, H>, version: u32) -> Side<K, V, H>
+where
+  K: Copy,
+  H: TreeHasher,
+{
+  let (left, right) = root.decompose().expect("rotate_left_right always sees an inner node");
+  let (l_left, l_right) = left.decompose().expect("rotate_left_right's left side is always an inner node");
+  let new_left = if l_right.height() > l_left.height() { side_rotate_left(left, version) } else { left };
+  side_rotate_right(side_inner(new_left, right, version), version)
+}
+
+// The generic counterpart of `Node::balance`: rebalances `node` (assumed to
+// be the freshly-combined parent of two already-balanced sides) using the
+// same rotations, falling straight through to `Node::balance` itself when
+// both sides turned out to be real.
+fn side_balance<K, V, H>(node: Side<K, V, H>, version: u32) -> Side<K, V, H>
+where
+  K: Copy,
+  H: TreeHasher,
+{
+  if let Side::Real(real) = &node {
+    return Side::Real(Node::balance(real.clone(), version));
+  }
+  let Some((left, right)) = node.decompose() else {
+    return node;
+  };
+  match left.height() as i16 - right.height() as i16 {
+    -1..=1 => node,
+    2 => side_rotate_left_right(node, version),
+    -2 => side_rotate_right_left(node, version),
+    _ => unreachable!(),
+  }
+}
+
+// Joins two finalized subtrees (every key under `left` sorts before every
+// key under `right`) into a single AVL-balanced result, following the
+// standard balanced-tree join algorithm: descend into whichever side is
+// more than one level taller, splice the other side in next to its last
+// child on that side, and rebalance back up with the same rotations
+// `insert` uses. Unlike nesting a multi-height stack with no rebalancing,
+// this always leaves the height-balance invariant (`height_difference`
+// within ±1) intact — which is what lets a tree built by `Restore` accept
+// further `insert`/`remove` calls without `Node::balance` ever hitting its
+// `unreachable!()` arm.
+fn join<K, V, H>(left: Side<K, V, H>, right: Side<K, V, H>, version: u32) -> Side<K, V, H>
+where
+  K: Copy,
+  H: TreeHasher,
+{
+  let (left_height, right_height) = (left.height(), right.height());
+  let combined = if left_height > right_height + 1 {
+    let (left_left, left_right) = left.decompose().expect("the taller side is always an inner node");
+    side_inner(left_left, join(left_right, right, version), version)
+  } else if right_height > left_height + 1 {
+    let (right_left, right_right) = right.decompose().expect("the taller side is always an inner node");
+    side_inner(join(left, right_left, version), right_right, version)
+  } else {
+    side_inner(left, right, version)
+  };
+  side_balance(combined, version)
+}
+
+// Which child of a parent node a proof step descended into; the step's
+// sibling hash is that of the other child.
+#[derive(Clone)]
+pub enum Direction {
+  Left,
+  Right,
+}
+
+// One level of a root-to-leaf path: the hash of the subtree we did *not*
+// descend into, plus the parent metadata needed to recompute its hash.
 #[derive(Clone)]
-pub enum Node<K, V> {
+pub struct ProofStep {
+  pub sibling_hash: [u8; 32],
+  pub direction: Direction,
+  pub height: u8,
+  pub version: u32,
+}
+
+// An inclusion proof for a single key: enough to fold the leaf hash back up
+// to the root hash and compare it against the committed root.
+#[derive(Clone)]
+pub struct MerkleProof {
+  pub leaf_version: u32,
+  // Steps are ordered root -> leaf; `verify_proof` folds them in reverse.
+  pub steps: Vec<ProofStep>,
+}
+
+// An absence proof: either the pair of adjacent leaves that bracket the
+// missing key, or a single edge leaf when the key falls outside the range
+// of keys present in the tree.
+#[derive(Clone)]
+pub enum ExclusionProof<K, V> {
+  Bracket {
+    low: (K, V, MerkleProof),
+    high: (K, V, MerkleProof),
+  },
+  Edge {
+    key: K,
+    value: V,
+    proof: MerkleProof,
+    // true if `key` is the smallest key in the tree (missing key sorts
+    // before it); false if it's the largest (missing key sorts after it).
+    is_low: bool,
+  },
+}
+
+// A node's children and hash are reached through `Arc` rather than `Box` so
+// that committing a version can retain the old root cheaply: only the
+// nodes on the path to a change are ever rebuilt, everything below the
+// lowest untouched ancestor is shared between versions by reference. `H`
+// picks the hash function baked into every node's `hash`; it defaults to
+// `Sha3Hasher` so existing `Node<K, V>` usages keep compiling unchanged.
+#[derive(Clone)]
+pub enum Node<K, V, H = Sha3Hasher> {
   Leaf {
     key: K,
     value: V,
     version: u32,
-    hash: Option<[u8; 32]>,
+    hash: [u8; 32],
+    // `H` only otherwise appears recursively (inside `Inner`'s children),
+    // which isn't enough to let the compiler use it to constrain variance;
+    // this marker is purely to make `H` a "real" field of the type.
+    _hasher: std::marker::PhantomData<H>,
   },
   Inner {
-    left: Option<Box<Node<K, V>>>,
-    right: Option<Box<Node<K, V>>>,
+    left: Arc<Node<K, V, H>>,
+    right: Arc<Node<K, V, H>>,
     key: K,
-    hash: Option<[u8; 32]>,
+    hash: [u8; 32],
     height: u8,
     version: u32,
   },
 }
 
+// Lets the generic `insert`/`remove` keep secondary indexes in sync without
+// `IAVL<K, V, H>` needing to know what an index is for arbitrary `K`/`V`.
+// Deliberately has no blanket impl: every concrete `K`/`V` instantiation
+// that goes through `insert`/`remove` must opt in explicitly (even if only
+// with the no-op default methods), so a future index can never be
+// silently bypassed by a type that forgot to wire itself up -- the
+// compiler refuses to build `IAVL<K, V, H>::insert`/`remove` for it at all.
+//
+// Both methods are handed the root as it stood *before* this mutation (to
+// look up a key's current value, if any) and return the indexes as they
+// should read *after* it. The caller folds the result into the same
+// `TreeState` as the new root and publishes both with one atomic store, so
+// a concurrent reader can never observe one updated without the other.
+pub trait IndexHook<K, V, H> {
+  fn reindex_on_insert(
+    &self,
+    indexes: &Arc<HashMap<IndexKey, HashSet<Pubkey>>>,
+    _root: &Option<Arc<Node<K, V, H>>>,
+    _key: &K,
+    _value: &V,
+  ) -> Arc<HashMap<IndexKey, HashSet<Pubkey>>> {
+    indexes.clone()
+  }
+
+  fn reindex_on_remove(
+    &self,
+    indexes: &Arc<HashMap<IndexKey, HashSet<Pubkey>>>,
+    _root: &Option<Arc<Node<K, V, H>>>,
+    _key: &K,
+  ) -> Arc<HashMap<IndexKey, HashSet<Pubkey>>> {
+    indexes.clone()
+  }
+}
+
+// The tree's root and its secondary indexes, published together as one
+// unit. Bundling them means a writer only ever needs a single atomic store
+// to advance both, so a reader's `ArcSwap::load` always sees a root and an
+// index view that agree with each other -- never a root from one mutation
+// paired with indexes from a different one.
+struct TreeState<K, V, H> {
+  root: Option<Arc<Node<K, V, H>>>,
+  indexes: Arc<HashMap<IndexKey, HashSet<Pubkey>>>,
+}
+
+impl<K, V, H> TreeState<K, V, H> {
+  fn empty() -> Arc<Self> {
+    Arc::new(TreeState { root: None, indexes: Arc::new(HashMap::new()) })
+  }
+}
+
+impl<K, V, H> Clone for TreeState<K, V, H> {
+  fn clone(&self) -> Self {
+    TreeState { root: self.root.clone(), indexes: self.indexes.clone() }
+  }
+}
+
 #[derive(Clone)]
-pub struct IAVL<K, V> {
-  pub root:  Arc<RwLock<Option<Box<Node<K, V>>>>>,
-  pub version: u32,
+pub struct IAVL<K, V, H = Sha3Hasher> {
+  // The published root and secondary indexes, as one unit. Readers reach
+  // it through `ArcSwap::load`, which is a lock-free atomic pointer read:
+  // a writer publishing a new state with `store` never blocks a
+  // concurrent reader, and a reader never blocks a concurrent writer. What
+  // a reader observes is always a complete, consistent snapshot, since
+  // publication is a single pointer swap.
+  state: Arc<ArcSwap<TreeState<K, V, H>>>,
+  // Serializes writers and holds the version the *next* published root will
+  // be stamped with. Plain `insert`/`remove`/`commit` and `write_txn` all
+  // take this lock, so only one writer is ever building a root at a time;
+  // readers never touch it.
+  writer: Arc<Mutex<u32>>,
+  // Historical roots retained by `commit`, keyed by the version they were
+  // frozen under. Because mutation is copy-on-write, an old root here and
+  // the live root share every subtree that hasn't changed since.
+  pub committed: Arc<RwLock<BTreeMap<u32, Option<Arc<Node<K, V, H>>>>>>,
+}
+
+// A cheap, non-blocking, point-in-time view of the tree: `IAVL::read_txn`
+// just clones the currently-published root `Arc`, so it never contends
+// with a concurrent writer and keeps observing the tree as it was at the
+// moment it was taken even after later writes publish a new root. Any
+// subtrees it references stay alive for as long as the guard is held,
+// even if `prune_to`/further commits drop them from `committed`.
+pub struct ReadTxn<K, V, H = Sha3Hasher> {
+  root: Option<Arc<Node<K, V, H>>>,
+  _hasher: std::marker::PhantomData<H>,
 }
 
-impl<K, V> Node<K, V> {
+impl<K, V, H> ReadTxn<K, V, H> {
+  pub fn get(&self, key: &K) -> Option<&V>
+  where
+    K: Ord,
+  {
+    self
+      .root
+      .as_ref()
+      .and_then(|root| Node::search(key, root.as_ref()))
+      .map(|(_, value)| value)
+  }
+
+  pub fn root_hash(&self) -> [u8; 32] {
+    self.root.as_ref().map(Node::node_hash).unwrap_or([0; 32])
+  }
+}
+
+// An in-progress write against the tree: `insert`/`remove` accumulate
+// against a private working root and index view that are invisible to
+// readers until `commit` publishes them, together, as one `TreeState`.
+// Taking a `WriteTxn` holds the single writer lock for its entire
+// lifetime, so at most one is ever open at a time; dropping it without
+// calling `commit` discards the accumulated changes.
+pub struct WriteTxn<'a, K, V, H = Sha3Hasher> {
+  iavl: &'a IAVL<K, V, H>,
+  guard: MutexGuard<'a, u32>,
+  root: Option<Arc<Node<K, V, H>>>,
+  indexes: Arc<HashMap<IndexKey, HashSet<Pubkey>>>,
+}
+
+impl<'a, K, V, H> WriteTxn<'a, K, V, H> {
+  pub fn insert(&mut self, key: K, value: V)
+  where
+    K: Ord + Copy + Encodable,
+    V: Encodable,
+    H: TreeHasher,
+    IAVL<K, V, H>: IndexHook<K, V, H>,
+  {
+    let version = *self.guard;
+    self.indexes = self.iavl.reindex_on_insert(&self.indexes, &self.root, &key, &value);
+    self.root = Some(match self.root.take() {
+      None => Node::leaf(key, value, version),
+      Some(root) => Node::insert(root, key, value, version),
+    });
+  }
+
+  pub fn remove(&mut self, key: &K)
+  where
+    K: Ord + Copy,
+    H: TreeHasher,
+    IAVL<K, V, H>: IndexHook<K, V, H>,
+  {
+    let version = *self.guard;
+    self.indexes = self.iavl.reindex_on_remove(&self.indexes, &self.root, key);
+    if let Some(root) = self.root.take() {
+      self.root = Node::remove(root, key, version);
+    }
+  }
+
+  // Publishes the accumulated root and indexes as one `TreeState`, retains
+  // the root under the committed version, and advances the tree to the
+  // next version. Returns the version just committed together with its
+  // root hash.
+  pub fn commit(mut self) -> (u32, [u8; 32]) {
+    let hash = self.root.as_ref().map(Node::node_hash).unwrap_or([0; 32]);
+    let committed_version = *self.guard;
+    self
+      .iavl
+      .state
+      .store(Arc::new(TreeState { root: self.root.clone(), indexes: self.indexes.clone() }));
+    self.iavl.committed.write().unwrap().insert(committed_version, self.root.take());
+    *self.guard = committed_version + 1;
+    (committed_version, hash)
+  }
+}
+
+impl<K, V, H> Node<K, V, H> {
   pub fn print(&self)
   where
     K: std::fmt::Display,
@@ -55,368 +580,884 @@ impl<K, V> Node<K, V> {
         ..
       } => {
         println!("Inner: key: {}, height: {}", key, height);
-        match left {
-          Some(l) => {
-            println!("LEFT:");
-            l.print();
-          }
-          None => {}
-        }
-        match right {
-          Some(r) => {
-            println!("RIGHT:");
-            r.print()
-          }
-          None => {}
-        }
+        println!("LEFT:");
+        left.print();
+        println!("RIGHT:");
+        right.print();
       }
     }
   }
 
-  fn new_leaf(key: K, value: V, version: u32) -> Self {
-    Node::Leaf {
-      key: key,
-      value: value,
-      hash: None,
-      version: version,
-    }
-  }
-  fn new_inner(key: K, left: Box<Node<K, V>>, right: Box<Node<K, V>>, version: u32) -> Node<K, V> {
-    Node::Inner {
-      key: key,
-      left: Some(left),
-      right: Some(right),
-      hash: None,
-      height: 1,
-      version: version,
-    }
-  }
-
-  fn insert_in_child(
-    root: Option<Box<Node<K, V>>>,
-    new_key: K,
-    new_value: V,
-    version: u32,
-  ) -> Option<Box<Node<K, V>>>
+  fn leaf(key: K, value: V, version: u32) -> Arc<Node<K, V, H>>
   where
-    K: Ord + Copy,
+    K: Encodable,
+    V: Encodable,
+    H: TreeHasher,
   {
-    Some(match root {
-      Some(node) => Node::insert(node, new_key, new_value, version),
-      None => Box::new(Node::new_leaf(new_key, new_value, version)),
-    })
+    let hash = H::hash_leaf(&key, &value, version);
+    Arc::new(Node::Leaf { key, value, version, hash, _hasher: std::marker::PhantomData })
   }
 
-  pub fn insert(
-    mut root: Box<Node<K, V>>,
-    new_key: K,
-    new_value: V,
-    version: u32,
-  ) -> Box<Node<K, V>>
+  // Builds an inner node and its hash in one step from already-hashed
+  // children, so a freshly constructed node never needs a follow-up pass
+  // to become verifiable.
+  fn inner(key: K, left: Arc<Node<K, V, H>>, right: Arc<Node<K, V, H>>, version: u32) -> Arc<Node<K, V, H>>
   where
-    K: Ord + Copy,
+    H: TreeHasher,
   {
-    match *root {
-      Node::Inner {
-        key,
-        ref mut right,
-        ref mut left,
-        ..
-      } => {
-        if new_key < key {
-          *left = Node::insert_in_child(left.take(), new_key, new_value, version)
-        } else {
-          *right = Node::insert_in_child(right.take(), new_key, new_value, version)
-        }
-      }
-      Node::Leaf { key, .. } => {
-        if new_key < key {
-          root = Box::new(Node::new_inner(
-            key,
-            Box::new(Node::new_leaf(new_key, new_value, version)),
-            root,
-            version,
-          ));
-        } else {
-          root = Box::new(Node::new_inner(
-            new_key,
-            root,
-            Box::new(Node::new_leaf(new_key, new_value, version)),
-            version,
-          ));
-        }
-      }
+    let height = cmp::max(Node::height(&left), Node::height(&right)) + 1;
+    let hash = H::hash_inner(height, version, &Node::node_hash(&left), &Node::node_hash(&right));
+    Arc::new(Node::Inner { key, left, right, hash, height, version })
+  }
+
+  pub fn height(node: &Arc<Node<K, V, H>>) -> u8 {
+    match node.as_ref() {
+      Node::Inner { height, .. } => *height,
+      Node::Leaf { .. } => 0,
     }
-    Node::update_height(&mut root);
-    Node::balance(root)
   }
 
-  pub fn height(root: &Option<Box<Node<K, V>>>) -> u8 {
-    match root {
-      Some(node) => match node.as_ref() {
-        Node::Inner { height, .. } => *height,
-        Node::Leaf { .. } => 0,
-      },
-      None => 0,
+  // Reads back a node's already-computed hash. `Node::inner`/`Node::leaf`
+  // compute this eagerly, so unlike the old mutating `update_hash` there is
+  // no "not yet hashed" case to handle.
+  pub fn node_hash(node: &Arc<Node<K, V, H>>) -> [u8; 32] {
+    match node.as_ref() {
+      Node::Leaf { hash, .. } => *hash,
+      Node::Inner { hash, .. } => *hash,
     }
   }
 
-  fn update_height(root: &mut Box<Node<K, V>>) {
-    match root.as_mut() {
-      Node::Inner {
-        ref left,
-        ref right,
-        ref mut height,
-        ..
-      } => {
-        *height = cmp::max(Node::height(left), Node::height(right)) + 1;
-      }
-      Node::Leaf { .. } => {}
+  fn as_leaf_kv(node: &Node<K, V, H>) -> (&K, &V) {
+    match node {
+      Node::Leaf { key, value, .. } => (key, value),
+      Node::Inner { .. } => unreachable!("expected a leaf node"),
     }
   }
 
-  pub fn update_hash(root: &mut Box<Node<K, V>>) -> [u8; 32] {
-    match root.as_mut() {
-      Node::Leaf { hash, .. } => {
-        // update hash
-        let h = [0; 32];
-        *hash = Some(h);
-        h
-      }
-      Node::Inner {
-        ref mut left,
-        ref mut right,
-        hash,
-        ..
-      } => {
-        let h_left = match left.as_mut() {
-          Some(node) => Node::update_hash(node),
-          None => [0; 32],
-        };
-        let h_right = match right.as_mut() {
-          Some(node) => Node::update_hash(node),
-          None => [0; 32],
-        };
-        let mut hasher = Sha3::sha3_256();
-        hasher.input(&h_left);
-        hasher.input(&h_right);
-        let mut h: [u8; 32] = [0; 32];
-        hasher.result(&mut h);
-        *hash = Some(h);
-        h
-      }
+  fn leftmost(node: &Arc<Node<K, V, H>>) -> &Arc<Node<K, V, H>> {
+    match node.as_ref() {
+      Node::Leaf { .. } => node,
+      Node::Inner { left, .. } => Node::leftmost(left),
     }
   }
 
-  fn rotate_right(mut root: Box<Node<K, V>>) -> Box<Node<K, V>> {
-    match *root {
-      Node::Leaf { .. } => unreachable!("Should not rotate leaf"),
-      Node::Inner {
-        left: ref mut root_left,
-        ..
-      } => {
-        let mut r = root_left.take().unwrap();
-        match r.as_mut() {
-          Node::Leaf { .. } => unreachable!("Broken algorithm"),
-          Node::Inner { ref mut right, .. } => {
-            *root_left = right.take();
-            Node::update_height(&mut root);
-            *right = Some(root);
-            Node::update_height(&mut r);
-          }
-        }
-        return r;
-      }
+  fn rightmost(node: &Arc<Node<K, V, H>>) -> &Arc<Node<K, V, H>> {
+    match node.as_ref() {
+      Node::Leaf { .. } => node,
+      Node::Inner { right, .. } => Node::rightmost(right),
     }
   }
 
-  fn rotate_right_left(mut root: Box<Node<K, V>>) -> Box<Node<K, V>> {
-    match *root {
-      Node::Leaf { .. } => unreachable!("Should not rotate leaf"),
-      Node::Inner {
-        right: ref mut root_right,
-        ..
-      } => {
-        let mut r = root_right.take().unwrap();
-        match r.as_mut() {
-          Node::Leaf { .. } => unreachable!("Broken algorithm"),
-          Node::Inner { right, left, .. } => {
-            if Node::get_height(left) > Node::get_height(right) {
-              let rotated_root = Node::rotate_right(r);
-              *root_right = Some(rotated_root);
-              Node::update_height(&mut root);
-            } else {
-              // Give back from take
-              *root_right = Some(r);
-            }
-          }
-        }
-        Node::rotate_left(root)
-      }
+  // The smallest key reachable under `node`; used to pick the split key
+  // when merging two finalized subtrees (it must be <= every key on the
+  // right so the usual `search_key < key` descent still lands correctly).
+  fn min_key(node: &Arc<Node<K, V, H>>) -> K
+  where
+    K: Copy,
+  {
+    match Node::leftmost(node).as_ref() {
+      Node::Leaf { key, .. } => *key,
+      Node::Inner { .. } => unreachable!("leftmost always returns a leaf"),
     }
   }
 
-  fn rotate_left(mut root: Box<Node<K, V>>) -> Box<Node<K, V>> {
-    match *root {
-      Node::Leaf { .. } => unreachable!("Should not rotate leaf"),
-      Node::Inner {
-        right: ref mut root_right,
-        ..
-      } => {
-        let mut r = root_right.take().unwrap();
-        match r.as_mut() {
-          Node::Leaf { .. } => unreachable!("Broken algorithm"),
-          Node::Inner { ref mut left, .. } => {
-            *root_right = left.take();
-            Node::update_height(&mut root);
-            *left = Some(root);
-            Node::update_height(&mut r);
-          }
+  // Copy-on-write insert: only the nodes along the path to `new_key` are
+  // rebuilt (stamped with `version`); every sibling subtree is shared with
+  // the previous tree via `Arc::clone`.
+  pub fn insert(root: Arc<Node<K, V, H>>, new_key: K, new_value: V, version: u32) -> Arc<Node<K, V, H>>
+  where
+    K: Ord + Copy + Encodable,
+    V: Encodable,
+    H: TreeHasher,
+  {
+    let updated = match root.as_ref() {
+      Node::Inner { key, left, right, .. } => {
+        if new_key < *key {
+          Node::inner(*key, Node::insert(left.clone(), new_key, new_value, version), right.clone(), version)
+        } else {
+          Node::inner(*key, left.clone(), Node::insert(right.clone(), new_key, new_value, version), version)
         }
-        return r;
       }
-    }
+      Node::Leaf { key, .. } => {
+        if new_key == *key {
+          // Updating an existing key replaces the leaf in place rather
+          // than nesting the old leaf as a "sibling" of the new one --
+          // otherwise the stale value would survive as a real leaf
+          // elsewhere in the tree, reachable again the moment `remove`
+          // walks down to it and (mis-)promotes it as this leaf's
+          // sibling. See the regression test `update_then_remove_does_not_resurrect_the_old_value`.
+          Node::leaf(new_key, new_value, version)
+        } else if new_key < *key {
+          Node::inner(*key, Node::leaf(new_key, new_value, version), root.clone(), version)
+        } else {
+          Node::inner(new_key, root.clone(), Node::leaf(new_key, new_value, version), version)
+        }
+      }
+    };
+    Node::balance(updated, version)
   }
 
-  fn rotate_left_right(mut root: Box<Node<K, V>>) -> Box<Node<K, V>> {
-    match *root {
-      Node::Leaf { .. } => unreachable!("Should not rotate leaf"),
-      Node::Inner {
-        left: ref mut root_left,
-        ..
-      } => {
-        let mut l = root_left.take().unwrap();
-        match l.as_mut() {
-          Node::Leaf { .. } => unreachable!("Broken algorithm"),
-          Node::Inner { left, right, .. } => {
-            if Node::get_height(right) > Node::get_height(left) {
-              let rotated_root = Node::rotate_left(l);
-              *root_left = Some(rotated_root);
-              Node::update_height(&mut root);
-            } else {
-              // Give back from take
-              *root_left = Some(l);
-            }
+  // Copy-on-write remove: `None` means this subtree was exactly the
+  // matching leaf and has been emptied, so the caller promotes its sibling
+  // in its place (an `Inner` node always keeps exactly two children).
+  pub fn remove(root: Arc<Node<K, V, H>>, key: &K, version: u32) -> Option<Arc<Node<K, V, H>>>
+  where
+    K: Ord + Copy,
+    H: TreeHasher,
+  {
+    match root.as_ref() {
+      Node::Leaf { key: leaf_key, .. } => {
+        if leaf_key == key {
+          None
+        } else {
+          Some(root.clone())
+        }
+      }
+      Node::Inner { key: split, left, right, .. } => {
+        if key < split {
+          match Node::remove(left.clone(), key, version) {
+            Some(new_left) => Some(Node::balance(Node::inner(*split, new_left, right.clone(), version), version)),
+            None => Some(right.clone()),
+          }
+        } else {
+          match Node::remove(right.clone(), key, version) {
+            Some(new_right) => Some(Node::balance(Node::inner(*split, left.clone(), new_right, version), version)),
+            None => Some(left.clone()),
           }
         }
-        Node::rotate_right(root)
       }
     }
   }
 
-  fn get_height(root: &Option<Box<Node<K, V>>>) -> u8 {
-    match root.as_ref() {
-      None => 0,
-      Some(node) => match node.as_ref() {
-        Node::Leaf { .. } => 0,
-        Node::Inner { height, .. } => *height,
-      },
+  fn decompose(node: &Arc<Node<K, V, H>>) -> (K, Arc<Node<K, V, H>>, Arc<Node<K, V, H>>)
+  where
+    K: Copy,
+  {
+    match node.as_ref() {
+      Node::Inner { key, left, right, .. } => (*key, left.clone(), right.clone()),
+      Node::Leaf { .. } => unreachable!("Broken algorithm"),
     }
   }
 
-  fn height_difference(root: &Box<Node<K, V>>) -> i8 {
+  fn rotate_right(root: Arc<Node<K, V, H>>, version: u32) -> Arc<Node<K, V, H>>
+  where
+    K: Copy,
+    H: TreeHasher,
+  {
+    let (root_key, r, root_right) = Node::decompose(&root);
+    let (r_key, r_left, r_right) = Node::decompose(&r);
+    let new_root = Node::inner(root_key, r_right, root_right, version);
+    Node::inner(r_key, r_left, new_root, version)
+  }
+
+  fn rotate_left(root: Arc<Node<K, V, H>>, version: u32) -> Arc<Node<K, V, H>>
+  where
+    K: Copy,
+    H: TreeHasher,
+  {
+    let (root_key, root_left, r) = Node::decompose(&root);
+    let (r_key, r_left, r_right) = Node::decompose(&r);
+    let new_root = Node::inner(root_key, root_left, r_left, version);
+    Node::inner(r_key, new_root, r_right, version)
+  }
+
+  fn rotate_right_left(root: Arc<Node<K, V, H>>, version: u32) -> Arc<Node<K, V, H>>
+  where
+    K: Copy,
+    H: TreeHasher,
+  {
+    let (key, left, right) = Node::decompose(&root);
+    let (_, r_left, r_right) = Node::decompose(&right);
+    let new_right = if Node::height(&r_left) > Node::height(&r_right) {
+      Node::rotate_right(right, version)
+    } else {
+      right
+    };
+    Node::rotate_left(Node::inner(key, left, new_right, version), version)
+  }
+
+  fn rotate_left_right(root: Arc<Node<K, V, H>>, version: u32) -> Arc<Node<K, V, H>>
+  where
+    K: Copy,
+    H: TreeHasher,
+  {
+    let (key, left, right) = Node::decompose(&root);
+    let (_, l_left, l_right) = Node::decompose(&left);
+    let new_left = if Node::height(&l_right) > Node::height(&l_left) {
+      Node::rotate_left(left, version)
+    } else {
+      left
+    };
+    Node::rotate_right(Node::inner(key, new_left, right, version), version)
+  }
+
+  fn height_difference(root: &Arc<Node<K, V, H>>) -> i8 {
     match root.as_ref() {
       Node::Leaf { .. } => 0,
-      Node::Inner { left, right, .. } => {
-        let l = Node::get_height(left);
-        let r = Node::get_height(right);
-        (l as i8) - (r as i8)
-      }
+      Node::Inner { left, right, .. } => (Node::height(left) as i8) - (Node::height(right) as i8),
     }
   }
 
-  fn balance(root: Box<Node<K, V>>) -> Box<Node<K, V>> {
+  fn balance(root: Arc<Node<K, V, H>>, version: u32) -> Arc<Node<K, V, H>>
+  where
+    K: Copy,
+    H: TreeHasher,
+  {
     let height_diff = Node::height_difference(&root);
     if height_diff >= -1 && height_diff <= 1 {
       return root;
     }
     match height_diff {
-      2 => Node::rotate_left_right(root),
-      -2 => Node::rotate_right_left(root),
+      2 => Node::rotate_left_right(root, version),
+      -2 => Node::rotate_right_left(root, version),
       _ => unreachable!(),
     }
   }
 }
 
-impl<'a, K: Ord, V> Node<K, V> {
-  pub fn search(search_key: &K, root: &'a Box<Node<K, V>>) -> Option<(&'a K, &'a V)> {
-    match root.as_ref() {
+impl<'a, K: Ord, V, H> Node<K, V, H> {
+  pub fn search(search_key: &K, root: &'a Node<K, V, H>) -> Option<(&'a K, &'a V)> {
+    match root {
       Node::Leaf { key, value, .. } => {
         if key == search_key {
-          Some((&key, &value))
+          Some((key, value))
         } else {
           None
         }
       }
-      Node::Inner {
-        key, left, right, ..
-      } => {
+      Node::Inner { key, left, right, .. } => {
         if search_key < key {
-          left
-            .as_ref()
-            .map_or(None, |node| Node::search(search_key, node))
+          Node::search(search_key, left.as_ref())
         } else {
-          right
-            .as_ref()
-            .map_or(None, |node| Node::search(search_key, node))
+          Node::search(search_key, right.as_ref())
+        }
+      }
+    }
+  }
+
+  // Walks root -> leaf along `key`'s search path, recording at each inner
+  // node the sibling subtree's hash and which side we descended to. Folding
+  // these steps back up from the leaf reproduces the root hash.
+  fn build_proof(search_key: &K, root: &'a Node<K, V, H>) -> Option<(&'a K, &'a V, MerkleProof)> {
+    let mut node = root;
+    let mut steps = Vec::new();
+    loop {
+      match node {
+        Node::Leaf { key, value, version, .. } => {
+          if key == search_key {
+            return Some((key, value, MerkleProof { leaf_version: *version, steps }));
+          } else {
+            return None;
+          }
+        }
+        Node::Inner {
+          key, left, right, height, version, ..
+        } => {
+          let go_left = search_key < key;
+          let (child, sibling) = if go_left { (left, right) } else { (right, left) };
+          steps.push(ProofStep {
+            sibling_hash: Node::node_hash(sibling),
+            direction: if go_left { Direction::Left } else { Direction::Right },
+            height: *height,
+            version: *version,
+          });
+          node = child.as_ref();
+        }
+      }
+    }
+  }
+
+  // Finds the predecessor and successor leaves that bracket `search_key`
+  // when `search_key` is absent from the tree. Either side may be `None` if
+  // `search_key` falls before the smallest or after the largest key held.
+  fn locate_neighbors(search_key: &K, root: &'a Node<K, V, H>) -> (Option<&'a Node<K, V, H>>, Option<&'a Node<K, V, H>>) {
+    match root {
+      Node::Leaf { key, .. } => {
+        if key < search_key {
+          (Some(root), None)
+        } else {
+          (None, Some(root))
+        }
+      }
+      Node::Inner { key, left, right, .. } => {
+        if search_key < key {
+          let (pred, succ) = Node::locate_neighbors(search_key, left.as_ref());
+          let succ = succ.or_else(|| Some(Node::leftmost(right).as_ref()));
+          (pred, succ)
+        } else {
+          let (pred, succ) = Node::locate_neighbors(search_key, right.as_ref());
+          let pred = pred.or_else(|| Some(Node::rightmost(left).as_ref()));
+          (pred, succ)
         }
       }
     }
   }
 }
 
-impl<K, V> IAVL<K, V> {
+impl<K, V, H> IAVL<K, V, H> {
   // Creates a new IAVL tree with no root and version 0
   pub fn new() -> Self {
       IAVL {
-          root: Arc::new(RwLock::new(None)), // Initialize the root with RwLock
-          version: 0,
+          state: Arc::new(ArcSwap::new(TreeState::empty())),
+          writer: Arc::new(Mutex::new(0)),
+          committed: Arc::new(RwLock::new(BTreeMap::new())),
       }
   }
 
-  // Inserts a new key-value pair into the IAVL tree
+  // The version the next committed root will be stamped with.
+  pub fn version(&self) -> u32 {
+      *self.writer.lock().unwrap()
+  }
+
+  // Takes a cheap, non-blocking snapshot of the currently published tree.
+  // Never contends with a concurrent writer: it is a single atomic pointer
+  // load, not a lock acquisition.
+  pub fn read_txn(&self) -> ReadTxn<K, V, H> {
+      ReadTxn { root: self.state.load().root.clone(), _hasher: std::marker::PhantomData }
+  }
+
+  // Opens a write transaction. Holds the single writer lock until the
+  // returned `WriteTxn` is dropped or committed, so at most one write
+  // transaction (and no plain `insert`/`remove`/`commit` call) is ever in
+  // flight at the same time.
+  pub fn write_txn(&self) -> WriteTxn<'_, K, V, H> {
+      let guard = self.writer.lock().unwrap();
+      let state = self.state.load();
+      WriteTxn { iavl: self, guard, root: state.root.clone(), indexes: state.indexes.clone() }
+  }
+
+  // Inserts a new key-value pair into the IAVL tree, keeping any secondary
+  // indexes (see `IndexHook`) in sync with the new value, and publishes
+  // both together as one `TreeState` so a concurrent reader never
+  // observes one updated without the other.
   pub fn insert(&mut self, new_key: K, new_value: V)
+  where
+      K: Ord + Copy + Encodable,
+      V: Encodable,
+      H: TreeHasher,
+      Self: IndexHook<K, V, H>,
+  {
+      let version = self.writer.lock().unwrap();
+      let state = self.state.load();
+      let new_indexes = self.reindex_on_insert(&state.indexes, &state.root, &new_key, &new_value);
+      let new_root = match &state.root {
+          // If the tree is empty, create a new leaf node as the root
+          None => Node::leaf(new_key, new_value, *version),
+          // Insert the new key-value pair into the existing tree
+          Some(root) => Node::insert(root.clone(), new_key, new_value, *version),
+      };
+      self.state.store(Arc::new(TreeState { root: Some(new_root), indexes: new_indexes }));
+  }
+
+  // Removes `key` from the tree, if present, and keeps secondary indexes
+  // (see `IndexHook`) in sync, publishing both together as one
+  // `TreeState`. A no-op on an empty tree or a missing key.
+  pub fn remove(&mut self, key: &K)
   where
       K: Ord + Copy,
+      H: TreeHasher,
+      Self: IndexHook<K, V, H>,
   {
-      // Acquire a write lock to modify the root
-      let mut root_guard = self.root.write().unwrap();
+      let version = self.writer.lock().unwrap();
+      let state = self.state.load();
+      let new_indexes = self.reindex_on_remove(&state.indexes, &state.root, key);
+      if let Some(root) = &state.root {
+          if Node::search(key, root.as_ref()).is_none() {
+              return;
+          }
+          let new_root = Node::remove(root.clone(), key, *version);
+          self.state.store(Arc::new(TreeState { root: new_root, indexes: new_indexes }));
+      }
+  }
 
-      match root_guard.take() {
-          None => {
-              // If the tree is empty, create a new leaf node as the root
-              *root_guard = Some(Box::new(Node::new_leaf(new_key, new_value, self.version)));
+  // Returns the tree's current root hash. Node hashes are maintained
+  // incrementally as `insert`/`remove` construct nodes, so this is a cheap
+  // read rather than a recomputation.
+  pub fn save_tree(&self) -> [u8; 32] {
+      self.state.load().root.as_ref().map(Node::node_hash).unwrap_or([0; 32])
+  }
+
+  // Freezes the current root under the current version, retains it in
+  // `committed` for later queries, and advances to the next version.
+  // Returns the version just committed together with its root hash.
+  pub fn commit(&mut self) -> (u32, [u8; 32]) {
+      let mut version = self.writer.lock().unwrap();
+      let snapshot = self.state.load().root.clone();
+      let hash = snapshot.as_ref().map(Node::node_hash).unwrap_or([0; 32]);
+      let committed_version = *version;
+      self.committed.write().unwrap().insert(committed_version, snapshot);
+      *version += 1;
+      (committed_version, hash)
+  }
+
+  // Looks up `key` as it existed in the state committed under `version`.
+  // Returns `None` if `version` was never committed or retained, or if the
+  // tree at that version has no such key.
+  pub fn get_at_version(&self, version: u32, key: &K) -> Option<V>
+  where
+      K: Ord,
+      V: Clone,
+  {
+      let committed = self.committed.read().unwrap();
+      let root = committed.get(&version)?.as_ref()?;
+      Node::search(key, root.as_ref()).map(|(_, value)| value.clone())
+  }
+
+  // Returns the root hash committed under `version`, if it is still
+  // retained.
+  pub fn root_hash_at(&self, version: u32) -> Option<[u8; 32]> {
+      let committed = self.committed.read().unwrap();
+      committed
+          .get(&version)
+          .map(|root| root.as_ref().map(Node::node_hash).unwrap_or([0; 32]))
+  }
+
+  // Drops retained historical roots older than `min_version`, bounding how
+  // much history `get_at_version`/`root_hash_at` can still serve.
+  pub fn prune_to(&mut self, min_version: u32) {
+      self.committed.write().unwrap().retain(|version, _| *version >= min_version);
+  }
+
+  // Builds an inclusion proof for `key`. Returns `None` if the key is
+  // absent or the tree is empty.
+  pub fn prove(&self, key: &K) -> Option<MerkleProof>
+  where
+      K: Ord,
+  {
+      let state = self.state.load();
+      let root = state.root.as_ref()?;
+      Node::build_proof(key, root.as_ref()).map(|(_, _, proof)| proof)
+  }
+
+  // Builds an absence proof for `key`: the proofs of the two leaves
+  // bracketing where `key` would sit, so a verifier can confirm no leaf
+  // with that key exists between them. Returns `None` if `key` is present
+  // (use `prove` instead) or the tree is empty.
+  pub fn prove_exclusion(&self, key: &K) -> Option<ExclusionProof<K, V>>
+  where
+      K: Ord + Clone,
+      V: Clone,
+  {
+      let state = self.state.load();
+      let root = state.root.as_ref()?.as_ref();
+      if Node::search(key, root).is_some() {
+          return None;
+      }
+      let (pred, succ) = Node::locate_neighbors(key, root);
+      match (pred, succ) {
+          (Some(p), Some(s)) => {
+              let (pk, pv) = Node::as_leaf_kv(p);
+              let (sk, sv) = Node::as_leaf_kv(s);
+              Some(ExclusionProof::Bracket {
+                  low: (pk.clone(), pv.clone(), Node::build_proof(pk, root)?.2),
+                  high: (sk.clone(), sv.clone(), Node::build_proof(sk, root)?.2),
+              })
+          }
+          (Some(p), None) => {
+              let (pk, pv) = Node::as_leaf_kv(p);
+              Some(ExclusionProof::Edge {
+                  key: pk.clone(),
+                  value: pv.clone(),
+                  proof: Node::build_proof(pk, root)?.2,
+                  is_low: false,
+              })
           }
-          Some(root) => {
-              // Insert the new key-value pair into the existing tree
-              *root_guard = Some(Node::insert(root, new_key, new_value, self.version));
+          (None, Some(s)) => {
+              let (sk, sv) = Node::as_leaf_kv(s);
+              Some(ExclusionProof::Edge {
+                  key: sk.clone(),
+                  value: sv.clone(),
+                  proof: Node::build_proof(sk, root)?.2,
+                  is_low: true,
+              })
           }
+          (None, None) => None,
       }
   }
+}
 
-  // Calculates and saves the tree's hash
-  pub fn save_tree(&self) -> [u8; 32] {
-      // Acquire a read lock to safely access and update the root hash
-      let mut root_guard = self.root.write().unwrap();
+// Recomputes the root hash implied by `(key, value, proof)` under hasher
+// `H` and compares it against `root_hash`. This is the verifier's half of
+// `IAVL::prove`: it never touches the tree itself, only the proof, and
+// must be called with the same `H` the tree was built with.
+pub fn verify_proof<K, V, H>(root_hash: [u8; 32], key: &K, value: &V, proof: &MerkleProof) -> bool
+where
+    K: Encodable,
+    V: Encodable,
+    H: TreeHasher,
+{
+    let mut hash = H::hash_leaf(key, value, proof.leaf_version);
+    for step in proof.steps.iter().rev() {
+        hash = match step.direction {
+            Direction::Left => H::hash_inner(step.height, step.version, &hash, &step.sibling_hash),
+            Direction::Right => H::hash_inner(step.height, step.version, &step.sibling_hash, &hash),
+        };
+    }
+    hash == root_hash
+}
+
+// True if every step in `steps` descended the same direction — i.e. the
+// leaf the steps lead to is the global minimum (all `Left`) or maximum
+// (all `Right`) of the tree the proof was built against.
+fn all_steps(steps: &[ProofStep], wanted: impl Fn(&Direction) -> bool) -> bool {
+    steps.iter().all(|step| wanted(&step.direction))
+}
+
+// True if `low`'s and `high`'s root-to-leaf paths are genuinely adjacent:
+// they share an identical prefix down to a common ancestor, diverge there
+// with `low` into the ancestor's left child and `high` into its right
+// child, and from that point on `low` always takes the rightmost child
+// while `high` always takes the leftmost. That is exactly "`low` is the
+// rightmost leaf of the common ancestor's left subtree and `high` is the
+// leftmost leaf of its right subtree" — the one structural condition that
+// rules out any other leaf of the tree sorting between them. Without this,
+// any two independently-verifying inclusion proofs that merely bracket a
+// key numerically could be spliced into a forged absence proof even when
+// the key is actually present somewhere between them.
+fn adjacent(low: &MerkleProof, high: &MerkleProof) -> bool {
+    let mut i = 0;
+    while i < low.steps.len() && i < high.steps.len() {
+        let l = &low.steps[i];
+        let h = &high.steps[i];
+        let same_branch = matches!(
+            (&l.direction, &h.direction),
+            (Direction::Left, Direction::Left) | (Direction::Right, Direction::Right)
+        );
+        if !same_branch || l.sibling_hash != h.sibling_hash || l.height != h.height || l.version != h.version {
+            break;
+        }
+        i += 1;
+    }
+    let (Some(diverge_low), Some(diverge_high)) = (low.steps.get(i), high.steps.get(i)) else {
+        return false;
+    };
+    matches!(diverge_low.direction, Direction::Left)
+        && matches!(diverge_high.direction, Direction::Right)
+        && all_steps(&low.steps[i + 1..], |d| matches!(d, Direction::Right))
+        && all_steps(&high.steps[i + 1..], |d| matches!(d, Direction::Left))
+}
+
+// Verifies an absence proof: every leaf named in the proof must verify
+// against `root_hash` under hasher `H`, `key` must sort correctly relative
+// to them, and — the part independent inclusion checks alone can't catch —
+// the proof must bind that the leaves are genuinely adjacent (`Bracket`) or
+// are the tree's actual global extreme (`Edge`), not just numerically on
+// the right side of `key`.
+pub fn verify_exclusion_proof<K, V, H>(root_hash: [u8; 32], key: &K, proof: &ExclusionProof<K, V>) -> bool
+where
+    K: Ord + Encodable,
+    V: Encodable,
+    H: TreeHasher,
+{
+    match proof {
+        ExclusionProof::Bracket { low, high } => {
+            let (lk, lv, lp) = low;
+            let (hk, hv, hp) = high;
+            lk < key
+                && key < hk
+                && verify_proof::<K, V, H>(root_hash, lk, lv, lp)
+                && verify_proof::<K, V, H>(root_hash, hk, hv, hp)
+                && adjacent(lp, hp)
+        }
+        ExclusionProof::Edge { key: edge_key, value, proof, is_low } => {
+            let ordered = if *is_low { key < edge_key } else { edge_key < key };
+            let extremal = if *is_low {
+                all_steps(&proof.steps, |d| matches!(d, Direction::Left))
+            } else {
+                all_steps(&proof.steps, |d| matches!(d, Direction::Right))
+            };
+            ordered && extremal && verify_proof::<K, V, H>(root_hash, edge_key, value, proof)
+        }
+    }
+}
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RestoreError {
+  // A chunk's keys were not strictly increasing, or did not continue
+  // strictly after the previous chunk's last key.
+  OutOfOrder,
+  // The tree folded so far (local progress plus the range proof's
+  // attested remainder) does not combine to the expected root.
+  RootMismatch,
+}
+
+impl std::fmt::Display for RestoreError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      RestoreError::OutOfOrder => write!(f, "chunk keys were not strictly increasing"),
+      RestoreError::RootMismatch => write!(f, "reconstructed root does not match the expected root"),
+    }
+  }
+}
+
+impl std::error::Error for RestoreError {}
+
+pub type Result<T> = std::result::Result<T, RestoreError>;
+
+// A node in a `RangeProof`'s frontier: a hash-only commitment to a subtree
+// of the as-yet-undelivered suffix, shaped exactly like a real `Node` (one
+// `height`/`hash` per level, recursively) so `join` can descend into and
+// rebalance around it exactly as it would a real subtree, without ever
+// needing the underlying keys or values.
+#[derive(Clone)]
+pub enum FrontierNode {
+  Leaf { hash: [u8; 32] },
+  Inner { height: u8, hash: [u8; 32], left: Box<FrontierNode>, right: Box<FrontierNode> },
+}
+
+impl FrontierNode {
+  fn height(&self) -> u8 {
+    match self {
+      FrontierNode::Leaf { .. } => 0,
+      FrontierNode::Inner { height, .. } => *height,
+    }
+  }
+
+  fn hash(&self) -> [u8; 32] {
+    match self {
+      FrontierNode::Leaf { hash } | FrontierNode::Inner { hash, .. } => *hash,
+    }
+  }
+}
+
+// A compact attestation, from the party streaming chunks, of the shape and
+// hashes of everything not yet delivered: a single subtree covering the
+// whole undelivered suffix, built the same way `Restore` builds its own
+// accumulator. `None` asserts that a chunk delivers the rest of the tree.
+#[derive(Clone)]
+pub struct RangeProof {
+  pub frontier: Option<FrontierNode>,
+}
+
+impl RangeProof {
+  pub fn final_chunk() -> Self {
+    RangeProof { frontier: None }
+  }
+}
+
+// Rebuilds an `IAVL<K, V, H>` from sorted `(key, value)` chunks streamed
+// from a trusted root hash, for verified bulk loading of a snapshot --
+// e.g. a freshly-exported genesis state, or any other one-shot dump of a
+// tree's full live key set. Each leaf is added to the accumulator exactly
+// as `insert` adds a new maximum key, so the result is the tree ascending
+// inserts build and always satisfies the AVL height-balance invariant —
+// unlike a plain bottom-up stack merge, which can glue together subtrees
+// of wildly different heights with no rebalancing and produce a tree
+// later `insert`/`remove` calls can't safely walk.
+//
+// `version` is folded into every leaf and inner hash, and `Restore` only
+// ever stamps ONE `version` across the whole reconstruction. That matches
+// a tree that was built by a single uninterrupted run of ascending
+// inserts (no `commit()` in between) -- which is exactly the genesis/
+// snapshot case this exists to serve, since `IAVL::commit()` never
+// re-stamps untouched subtrees, so `expected_root` must come from a
+// snapshot taken after its first commit, not an arbitrary later one: once
+// a tree has lived through more than one commit, different subtrees carry
+// different baked-in versions, and no uniform `version` can reproduce
+// that root from `(key, value)` pairs alone. `add_chunk` will correctly
+// report `RootMismatch` rather than silently accepting a wrong root in
+// that case -- see `restore_rejects_a_root_built_across_multiple_commits`.
+pub struct Restore<K, V, H = Sha3Hasher> {
+  expected_root: [u8; 32],
+  version: u32,
+  acc: Option<Arc<Node<K, V, H>>>,
+  last_key: Option<K>,
+}
 
-      match root_guard.as_mut() {
-          None => [0; 32], // Return a zeroed hash if the tree is empty
-          Some(root) => Node::update_hash(root), // Update and return the hash of the tree
+impl<K, V, H> Restore<K, V, H>
+where
+  K: Ord + Copy + Encodable,
+  V: Clone + Encodable,
+  H: TreeHasher,
+{
+  // `expected_root` must be the root of a tree that was built by a single
+  // run of ascending inserts under `version` with no commit in between
+  // (see the struct-level doc comment) -- a snapshot taken right after
+  // its first commit, not an arbitrary later one.
+  pub fn new(expected_root: [u8; 32], version: u32) -> Self {
+    Restore {
+      expected_root,
+      version,
+      acc: None,
+      last_key: None,
+    }
+  }
+
+  // Adds a new maximum key to the accumulator exactly as `insert` would,
+  // so the accumulator always has the shape ascending inserts produce.
+  fn push(&mut self, key: K, value: V) {
+    self.acc = Some(match self.acc.take() {
+      None => Node::leaf(key, value, self.version),
+      Some(acc) => Node::insert(acc, key, value, self.version),
+    });
+  }
+
+  pub fn add_chunk(&mut self, pairs: &[(K, V)], range_proof: RangeProof) -> Result<()> {
+    for (key, value) in pairs {
+      if let Some(last) = self.last_key {
+        if *key <= last {
+          return Err(RestoreError::OutOfOrder);
+        }
       }
+      self.last_key = Some(*key);
+      self.push(*key, value.clone());
+    }
+    if self.projected_root(&range_proof) != self.expected_root {
+      return Err(RestoreError::RootMismatch);
+    }
+    Ok(())
+  }
+
+  // Joins the accumulator built so far with the range proof's attested
+  // remainder into a single candidate root hash.
+  fn projected_root(&self, range_proof: &RangeProof) -> [u8; 32] {
+    let local = self.acc.clone().map(Side::Real);
+    match (local, range_proof.frontier.clone().map(Side::Frontier)) {
+      (None, None) => [0; 32],
+      (Some(only), None) | (None, Some(only)) => only.hash(),
+      (Some(local), Some(remainder)) => join(local, remainder, self.version).hash(),
+    }
+  }
+
+  // Checks the accumulator against the expected root and, if
+  // it matches, hands back a working tree built from it.
+  pub fn finish(self) -> Result<IAVL<K, V, H>> {
+    let hash = self.acc.as_ref().map(Node::node_hash).unwrap_or([0; 32]);
+    if hash != self.expected_root {
+      return Err(RestoreError::RootMismatch);
+    }
+    let iavl = IAVL::new();
+    iavl.state.store(Arc::new(TreeState { root: self.acc, indexes: Arc::new(HashMap::new()) }));
+    *iavl.writer.lock().unwrap() = self.version + 1;
+    Ok(iavl)
   }
 }
 
+// A secondary-index lookup key over account entries. `ProgramId` comes
+// straight from `account.owner()`; the SPL-token variants are parsed out of
+// the account's raw data when that owner is the SPL Token program.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub enum IndexKey {
+  ProgramId(Pubkey),
+  SplTokenOwner(Pubkey),
+  SplTokenMint(Pubkey),
+}
+
+// SPL Token `Account` layout is fixed-offset: mint (0..32), owner (32..64),
+// amount (64..72), ... for a total length of 165 bytes.
+const SPL_TOKEN_ACCOUNT_LEN: usize = 165;
+
+fn spl_token_program_id() -> Pubkey {
+  Pubkey::from_str("TokenkegQfeZyiNwAJbNbGKPFXCWuBvf9Ss623VQ5DA").expect("valid base58 pubkey")
+}
+
+// Every secondary-index key `account` should currently be reachable under:
+// always its owning program, plus (when it looks like an SPL token
+// account) the token's owner and mint.
+fn account_index_keys(account: &AccountSharedData) -> Vec<IndexKey> {
+  let mut keys = vec![IndexKey::ProgramId(*account.owner())];
+  if *account.owner() == spl_token_program_id() && account.data().len() >= SPL_TOKEN_ACCOUNT_LEN {
+    let data = account.data();
+    if let Ok(mint) = Pubkey::try_from(&data[0..32]) {
+      keys.push(IndexKey::SplTokenMint(mint));
+    }
+    if let Ok(owner) = Pubkey::try_from(&data[32..64]) {
+      keys.push(IndexKey::SplTokenOwner(owner));
+    }
+  }
+  keys
+}
+
+// Removes `pubkey`'s entries (as `account_index_keys(old_account)` read
+// before this mutation) from `indexes`, in place.
+fn unindex_in(indexes: &mut HashMap<IndexKey, HashSet<Pubkey>>, pubkey: &Pubkey, old_account: &AccountSharedData) {
+  for key in account_index_keys(old_account) {
+    if let Some(set) = indexes.get_mut(&key) {
+      set.remove(pubkey);
+      if set.is_empty() {
+        indexes.remove(&key);
+      }
+    }
+  }
+}
 
-impl TransactionProcessingCallback for IAVL<Pubkey, AccountSharedData> {
+// Adds `pubkey`'s entries (as `account_index_keys(account)` reads now) to
+// `indexes`, in place.
+fn index_in(indexes: &mut HashMap<IndexKey, HashSet<Pubkey>>, pubkey: &Pubkey, account: &AccountSharedData) {
+  for key in account_index_keys(account) {
+    indexes.entry(key).or_default().insert(*pubkey);
+  }
+}
+
+// Wires the account tree's generic `insert`/`remove` (and `WriteTxn`'s) up
+// to the secondary indexes, so there is no separate unindexed path left to
+// call by mistake. Both methods are pure: they look the key's prior value
+// up in `root` (the state as it stood before this mutation) and return the
+// indexes as they should read after, so the caller can publish the new
+// root and the new indexes together in one atomic step -- see
+// `IAVL::insert`/`remove` and `WriteTxn::insert`/`remove`.
+impl<H: TreeHasher> IndexHook<Pubkey, AccountSharedData, H> for IAVL<Pubkey, AccountSharedData, H> {
+  fn reindex_on_insert(
+    &self,
+    indexes: &Arc<HashMap<IndexKey, HashSet<Pubkey>>>,
+    root: &Option<Arc<Node<Pubkey, AccountSharedData, H>>>,
+    pubkey: &Pubkey,
+    account: &AccountSharedData,
+  ) -> Arc<HashMap<IndexKey, HashSet<Pubkey>>> {
+    let mut next = (**indexes).clone();
+    if let Some((_, old_account)) = root.as_ref().and_then(|root| Node::search(pubkey, root.as_ref())) {
+      unindex_in(&mut next, pubkey, old_account);
+    }
+    index_in(&mut next, pubkey, account);
+    Arc::new(next)
+  }
+
+  fn reindex_on_remove(
+    &self,
+    indexes: &Arc<HashMap<IndexKey, HashSet<Pubkey>>>,
+    root: &Option<Arc<Node<Pubkey, AccountSharedData, H>>>,
+    pubkey: &Pubkey,
+  ) -> Arc<HashMap<IndexKey, HashSet<Pubkey>>> {
+    let Some((_, old_account)) = root.as_ref().and_then(|root| Node::search(pubkey, root.as_ref())) else {
+      return indexes.clone();
+    };
+    let mut next = (**indexes).clone();
+    unindex_in(&mut next, pubkey, old_account);
+    Arc::new(next)
+  }
+}
+
+impl<H: TreeHasher> IAVL<Pubkey, AccountSharedData, H> {
+  // Returns every `(pubkey, account)` currently tracked under `key`, read
+  // from a single atomic snapshot so the index and the accounts it points
+  // at always agree with each other, even with a concurrent writer.
+  pub fn scan_by_index(&self, key: IndexKey) -> Vec<(Pubkey, AccountSharedData)> {
+    let state = self.state.load();
+    let Some(root) = state.root.as_ref() else {
+      return Vec::new();
+    };
+    state
+      .indexes
+      .get(&key)
+      .into_iter()
+      .flatten()
+      .filter_map(|pubkey| Node::search(pubkey, root.as_ref()).map(|(_, account)| (*pubkey, account.clone())))
+      .collect()
+  }
+}
+
+impl<H: TreeHasher> TransactionProcessingCallback for IAVL<Pubkey, AccountSharedData, H> {
   // Method to check if the account's owner matches any of the provided owners
   fn account_matches_owners(&self, account: &Pubkey, owners: &[Pubkey]) -> Option<usize> {
-    // Acquire a read lock on the RwLock to safely access the root node
-    let root_guard = self.root.read().unwrap(); // Locking for read access
+    // Lock-free atomic pointer load: never blocks a concurrent writer.
+    let state = self.state.load();
 
     // Safely access the root and perform the search
-    if let Some(data) = root_guard
-        .as_ref() // Access the Option inside the RwLock
-        .and_then(|root| Node::search(account, root))
+    if let Some(data) = state
+        .root
+        .as_ref()
+        .and_then(|root| Node::search(account, root.as_ref()))
     {
         // Check if the account has zero lamports (inactive)
         if data.1.lamports() == 0 {
@@ -432,13 +1473,13 @@ impl TransactionProcessingCallback for IAVL<Pubkey, AccountSharedData> {
 
   // Method to retrieve the shared data of a given account
     fn get_account_shared_data(&self, pubkey: &Pubkey) -> Option<AccountSharedData> {
-        // Acquire a read lock on the root to access the data safely
-        let root_guard = self.root.read().unwrap(); // Locking for read access
+        // Lock-free atomic pointer load: never blocks a concurrent writer.
+        let state = self.state.load();
 
-        // Safely access the root and search for the account data
-        root_guard
-            .as_ref() // Access the Option inside the RwLock
-            .and_then(|root| Node::search(pubkey, root)) // Search for the account in the tree
+        state
+            .root
+            .as_ref()
+            .and_then(|root| Node::search(pubkey, root.as_ref())) // Search for the account in the tree
             .map(|(_, account_data)| account_data.clone()) // Clone the found account data
     }
 
@@ -448,58 +1489,260 @@ impl TransactionProcessingCallback for IAVL<Pubkey, AccountSharedData> {
       // Create the account using the native loader utility
       let account_data = native_loader::create_loadable_account_with_fields(name, (5000, 0));
 
-      // Use a write lock to gain mutable access to self.root
-      let mut root = self.root.write().unwrap(); // Using RwLock for safe mutable access
-
-      // Insert the new account into the IAVL tree
-      match root.take() {
-          Some(existing_root) => {
-              // Insert account data into the existing tree
-              *root = Some(Node::insert(existing_root, *program_id, account_data, self.version));
-          }
-          None => {
-              // If the tree is empty, create a new root node with the account
-              *root = Some(Box::new(Node::new_leaf(*program_id, account_data, self.version)));
-          }
-      }
+      // Take the single writer lock for the duration of the build, and
+      // publish the new root together with the reindexed secondary
+      // indexes in one atomic store, the same way `insert` does.
+      let version = self.writer.lock().unwrap();
+      let state = self.state.load();
+      let new_indexes = self.reindex_on_insert(&state.indexes, &state.root, program_id, &account_data);
+      let new_root = match &state.root {
+          // Insert account data into the existing tree
+          Some(existing_root) => Node::insert(existing_root.clone(), *program_id, account_data, *version),
+          // If the tree is empty, create a new root node with the account
+          None => Node::leaf(*program_id, account_data, *version),
+      };
+      self.state.store(Arc::new(TreeState { root: Some(new_root), indexes: new_indexes }));
   }
 }
 
-// #[cfg(test)]
-// mod tests {
-//   use super::*;
-
-//   #[test]
-//   fn construct_tree() {
-//     let mut iavl = IAVL::new();
-//     iavl.insert(4, 4);
-//   }
-
-//   #[test]
-//   fn search() {
-//     let mut iavl = IAVL::new();
-//     for i in 0..10 {
-//       iavl.insert(i, i);
-//     }
-//     let root = &iavl.root.unwrap();
-//     let s = Node::search(&11, root);
-//     match s {
-//       None => {}
-//       Some(_) => assert!(false),
-//     }
-//     let s = Node::search(&4, root);
-//     match s {
-//       None => assert!(false),
-//       Some(_) => {}
-//     }
-//   }
-
-//   #[test]
-//   fn calculate_hash() {
-//     let mut iavl = IAVL::new();
-//     for i in 0..10 {
-//       iavl.insert(i, i);
-//     }
-//     iavl.save_tree();
-//   }
-// }
+// The generic `insert`/`remove` require an explicit `IndexHook` opt-in (see
+// its definition), and these tests exercise `IAVL<i32, i32, H>` directly --
+// there's no secondary index to keep in sync for plain integers, so the
+// no-op defaults are all that's needed.
+impl<H> IndexHook<i32, i32, H> for IAVL<i32, i32, H> {}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn ascending_tree(n: i32) -> IAVL<i32, i32, Sha3Hasher> {
+    let mut iavl = IAVL::new();
+    for i in 0..n {
+      iavl.insert(i, i * 10);
+    }
+    iavl
+  }
+
+  fn even_keyed_tree(n: i32) -> IAVL<i32, i32, Sha3Hasher> {
+    let mut iavl = IAVL::new();
+    for i in 0..n {
+      iavl.insert(i * 2, i * 2 * 10);
+    }
+    iavl
+  }
+
+  #[test]
+  fn construct_and_search() {
+    let iavl = ascending_tree(10);
+    assert_eq!(Node::search(&4, iavl.state.load().root.as_ref().unwrap()), Some((&4, &40)));
+    assert_eq!(Node::search(&11, iavl.state.load().root.as_ref().unwrap()), None);
+  }
+
+  #[test]
+  fn inclusion_proof_round_trips() {
+    let iavl = ascending_tree(20);
+    let root_hash = iavl.save_tree();
+    for key in 0..20 {
+      let proof = iavl.prove(&key).expect("key is present");
+      assert!(verify_proof::<i32, i32, Sha3Hasher>(root_hash, &key, &(key * 10), &proof));
+    }
+  }
+
+  #[test]
+  fn inclusion_proof_rejects_wrong_value() {
+    let iavl = ascending_tree(20);
+    let root_hash = iavl.save_tree();
+    let proof = iavl.prove(&5).unwrap();
+    assert!(!verify_proof::<i32, i32, Sha3Hasher>(root_hash, &5, &999, &proof));
+  }
+
+  #[test]
+  fn exclusion_proof_bracket_round_trips() {
+    let iavl = even_keyed_tree(10); // keys 0, 2, 4, ..., 18
+    let root_hash = iavl.save_tree();
+    let proof = iavl.prove_exclusion(&5).expect("5 is absent");
+    assert!(verify_exclusion_proof::<i32, i32, Sha3Hasher>(root_hash, &5, &proof));
+  }
+
+  #[test]
+  fn exclusion_proof_edge_cases_round_trip() {
+    let iavl = even_keyed_tree(10); // keys 0, 2, 4, ..., 18
+    let root_hash = iavl.save_tree();
+    let below = iavl.prove_exclusion(&-1).expect("-1 sorts before every key");
+    assert!(verify_exclusion_proof::<i32, i32, Sha3Hasher>(root_hash, &-1, &below));
+    let above = iavl.prove_exclusion(&100).expect("100 sorts after every key");
+    assert!(verify_exclusion_proof::<i32, i32, Sha3Hasher>(root_hash, &100, &above));
+  }
+
+  // Regression test for a forged exclusion proof: two individually-valid
+  // inclusion proofs for the tree's global min and max leaves are spliced
+  // together into a `Bracket` for a key that sorts between them. The old
+  // `verify_exclusion_proof` only checked independent inclusion plus key
+  // ordering, so it accepted this even though 8 other keys (2..=16) sit
+  // between the spliced leaves. The fixed version must reject it.
+  #[test]
+  fn exclusion_proof_rejects_non_adjacent_bracket() {
+    let iavl = even_keyed_tree(10); // keys 0, 2, 4, ..., 18
+    let root_hash = iavl.save_tree();
+    let low_proof = iavl.prove(&0).unwrap();
+    let high_proof = iavl.prove(&18).unwrap();
+    let forged = ExclusionProof::Bracket {
+      low: (0, 0, low_proof),
+      high: (18, 180, high_proof),
+    };
+    assert!(!verify_exclusion_proof::<i32, i32, Sha3Hasher>(root_hash, &9, &forged));
+  }
+
+  // Regression test: `insert` on an already-present key must replace the
+  // leaf in place. It previously nested the stale old leaf as a "sibling"
+  // of the new one (both carrying the same key), so `remove`'s None-
+  // propagation could walk down to the live (updated) leaf, delete it,
+  // and promote the stale old leaf as though it were a structural
+  // sibling -- silently resurrecting the pre-update value.
+  #[test]
+  fn update_then_remove_does_not_resurrect_the_old_value() {
+    let mut iavl = ascending_tree(10); // keys 0..10 (values key*10)
+    iavl.insert(5, 50);
+    iavl.insert(5, 9999);
+    assert_eq!(Node::search(&5, iavl.state.load().root.as_ref().unwrap()), Some((&5, &9999)));
+
+    iavl.remove(&5);
+    assert_eq!(Node::search(&5, iavl.state.load().root.as_ref().unwrap()), None);
+  }
+
+  // Regression test: `Restore` must reproduce the exact root a normal,
+  // sequential `insert`-built tree commits — not just some AVL-balanced
+  // tree with the same keys. Previously `Restore` nested unequal-height
+  // subtrees with no rebalancing (able to violate the height-balance
+  // invariant outright) and, even when it didn't, built a different shape
+  // than ascending `insert` does for the same keys, so real snapshots
+  // would fail with a spurious `RootMismatch`.
+  #[test]
+  fn restore_matches_a_real_committed_root() {
+    let source = ascending_tree(50);
+    let expected_root = source.save_tree();
+    let pairs: Vec<(i32, i32)> = (0..50).map(|i| (i, i * 10)).collect();
+
+    let mut restore = Restore::<i32, i32, Sha3Hasher>::new(expected_root, source.version());
+    restore.add_chunk(&pairs, RangeProof::final_chunk()).expect("matches the real root");
+    let mut restored = restore.finish().expect("root matched");
+
+    assert_eq!(restored.save_tree(), expected_root);
+    assert_eq!(Node::search(&25, restored.state.load().root.as_ref().unwrap()), Some((&25, &250)));
+
+    // The restored tree must satisfy the same height-balance invariant a
+    // sequentially-built tree does, so it can keep accepting ordinary
+    // mutations without `Node::balance` hitting its `unreachable!()` arm.
+    restored.insert(50, 500);
+    restored.remove(&0);
+  }
+
+  // `Restore` must also reject a chunk whose projected root (local
+  // accumulator joined with the attested frontier) doesn't match the
+  // expected root, the same way it would for a forged/corrupted snapshot.
+  #[test]
+  fn restore_rejects_a_mismatched_root() {
+    let source = ascending_tree(10);
+    let wrong_root = [0xAB; 32];
+    let pairs: Vec<(i32, i32)> = (0..10).map(|i| (i, i * 10)).collect();
+
+    let mut restore = Restore::<i32, i32, Sha3Hasher>::new(wrong_root, source.version());
+    assert!(matches!(
+      restore.add_chunk(&pairs, RangeProof::final_chunk()),
+      Err(RestoreError::RootMismatch)
+    ));
+  }
+
+  // Regression test documenting `Restore`'s scope: it reproduces a tree
+  // built by one uninterrupted run of ascending inserts, not an arbitrary
+  // tree with a multi-commit history. `IAVL::commit()` never re-stamps
+  // untouched subtrees, so after two rounds of insert+commit the live
+  // tree has SOME subtrees baked at version 0 and others at version 1 --
+  // no single uniform `version` passed to `Restore` can reproduce that
+  // root from `(key, value)` pairs alone, since the pairs carry no
+  // per-subtree version information. `add_chunk` must report
+  // `RootMismatch` here rather than silently restoring the wrong tree.
+  #[test]
+  fn restore_rejects_a_root_built_across_multiple_commits() {
+    let mut source = ascending_tree(10); // keys 0..10, values i*10, all at version 0
+    source.commit();
+    source.insert(100, 1000); // rebuilds only the path to 100, now at version 1
+    let (final_version, final_root) = source.commit();
+
+    let mut pairs: Vec<(i32, i32)> = (0..10).map(|i| (i, i * 10)).collect();
+    pairs.push((100, 1000));
+    pairs.sort();
+
+    let mut restore = Restore::<i32, i32, Sha3Hasher>::new(final_root, final_version);
+    assert!(matches!(
+      restore.add_chunk(&pairs, RangeProof::final_chunk()),
+      Err(RestoreError::RootMismatch)
+    ));
+  }
+
+  #[test]
+  fn get_at_version_and_prune_to_track_committed_history() {
+    let mut iavl = ascending_tree(5); // keys 0..5, uncommitted
+    let (v0, hash0) = iavl.commit();
+    iavl.insert(100, 1000);
+    let (v1, hash1) = iavl.commit();
+
+    assert_eq!(iavl.root_hash_at(v0), Some(hash0));
+    assert_eq!(iavl.root_hash_at(v1), Some(hash1));
+    assert_eq!(iavl.get_at_version(v0, &100), None);
+    assert_eq!(iavl.get_at_version(v1, &100), Some(1000));
+    assert_eq!(iavl.get_at_version(v0, &2), Some(20));
+
+    iavl.prune_to(v1);
+    assert_eq!(iavl.root_hash_at(v0), None);
+    assert_eq!(iavl.get_at_version(v0, &2), None);
+    assert_eq!(iavl.root_hash_at(v1), Some(hash1));
+  }
+
+  // `read_txn` must keep observing the tree as it stood when it was taken,
+  // even while a `write_txn` accumulates changes against it and even after
+  // a fresh `read_txn` is taken in between -- only `commit` should make the
+  // write visible.
+  #[test]
+  fn read_txn_is_isolated_from_an_uncommitted_write_txn() {
+    let mut iavl = ascending_tree(5); // keys 0..5 (values key*10)
+    let snapshot = iavl.read_txn();
+    assert_eq!(snapshot.get(&2), Some(&20));
+
+    let mut write_txn = iavl.write_txn();
+    write_txn.insert(2, 999);
+    write_txn.insert(100, 1000);
+
+    assert_eq!(snapshot.get(&2), Some(&20));
+    assert_eq!(iavl.read_txn().get(&2), Some(&20));
+    assert_eq!(iavl.read_txn().get(&100), None);
+
+    write_txn.commit();
+    assert_eq!(iavl.read_txn().get(&2), Some(&999));
+    assert_eq!(iavl.read_txn().get(&100), Some(&1000));
+  }
+
+  // Regression test for the account tree's secondary indexes: plain
+  // `insert`/`remove` (not a separate `insert_account`/`remove_account`
+  // path) must keep `scan_by_index` consistent, including moving an
+  // account out of its old owner's index when its owner changes.
+  #[test]
+  fn account_insert_and_remove_keep_scan_by_index_consistent() {
+    let mut iavl: IAVL<Pubkey, AccountSharedData, Sha3Hasher> = IAVL::new();
+    let program_a = Pubkey::new_unique();
+    let program_b = Pubkey::new_unique();
+    let pubkey = Pubkey::new_unique();
+
+    let account = AccountSharedData::new(100, 0, &program_a);
+    iavl.insert(pubkey, account.clone());
+    assert_eq!(iavl.scan_by_index(IndexKey::ProgramId(program_a)), vec![(pubkey, account)]);
+
+    let moved = AccountSharedData::new(100, 0, &program_b);
+    iavl.insert(pubkey, moved.clone());
+    assert_eq!(iavl.scan_by_index(IndexKey::ProgramId(program_a)), Vec::new());
+    assert_eq!(iavl.scan_by_index(IndexKey::ProgramId(program_b)), vec![(pubkey, moved)]);
+
+    iavl.remove(&pubkey);
+    assert_eq!(iavl.scan_by_index(IndexKey::ProgramId(program_b)), Vec::new());
+  }
+}